@@ -18,25 +18,37 @@ pub mod test_utils;
 
 pub use self::futures::FutureExt;
 use crate::errors::CoreError;
+use aead::generic_array::GenericArray;
+use aead::{Aead as AeadCipher, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
 use bincode::{deserialize, serialize};
-use miscreant::aead::Aead;
+use chacha20poly1305::ChaCha20Poly1305;
+use miscreant::aead::Aead as SivAead;
 use miscreant::Aes128SivAead;
 use rand::distributions::{Alphanumeric, Distribution, Standard};
 use rand::rngs::OsRng;
 use rand::{self, Rng};
 use rust_sodium::crypto::hash::sha512::{self, Digest, DIGESTBYTES};
+use scrypt::{scrypt, ScryptParams};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use zeroize::Zeroize;
 
 /// Length of the symmetric encryption key.
 pub const SYM_ENC_KEY_LEN: usize = 32;
 
-/// Length of the nonce used for symmetric encryption.
+/// Length of the nonce used for symmetric encryption with the default AES-128-SIV suite.
 pub const SYM_ENC_NONCE_LEN: usize = 16;
 
+/// Length of the nonce used by the ChaCha20-Poly1305 and AES-256-GCM cipher suites.
+pub const AEAD_NONCE_LEN: usize = 12;
+
 /// Symmetric encryption key
 pub type SymEncKey = [u8; SYM_ENC_KEY_LEN];
 
-/// Symmetric encryption nonce
+/// Symmetric encryption nonce (AES-128-SIV only; the other suites use a 12-byte nonce - see
+/// `AEAD_NONCE_LEN`).
 pub type SymEncNonce = [u8; SYM_ENC_NONCE_LEN];
 
 /// Easily create a BTreeSet.
@@ -77,13 +89,110 @@ macro_rules! btree_map {
 
 #[derive(Serialize, Deserialize)]
 struct SymmetricEnc {
+    suite: u8,
+    nonce: Vec<u8>,
+    cipher_text: Vec<u8>,
+}
+
+/// Wire format used before cipher suites were tagged: an untagged AES-128-SIV nonce followed by
+/// the cipher text, with no suite byte. Kept so `symmetric_decrypt` can still read blobs written
+/// before `CipherSuite` existed; never written by `symmetric_encrypt*` any more.
+#[derive(Serialize, Deserialize)]
+struct LegacySymmetricEnc {
     nonce: SymEncNonce,
     cipher_text: Vec<u8>,
 }
 
+/// Selects which AEAD algorithm `symmetric_encrypt_with_suite` encrypts with. The blob is
+/// tagged with the suite it was written with, so `symmetric_decrypt` always dispatches to the
+/// right algorithm - old data keeps decrypting under whatever suite it was created with even
+/// after the crate's default changes, and callers can pick a different suite outright (e.g.
+/// ChaCha20-Poly1305 on platforms without AES hardware).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CipherSuite {
+    /// AES-128-SIV. Deterministic and misuse-resistant; the crate's original cipher and current
+    /// default.
+    Aes128Siv,
+    /// ChaCha20-Poly1305.
+    ChaCha20Poly1305,
+    /// AES-256-GCM.
+    Aes256Gcm,
+}
+
+impl Default for CipherSuite {
+    fn default() -> Self {
+        CipherSuite::Aes128Siv
+    }
+}
+
+impl CipherSuite {
+    fn tag(self) -> u8 {
+        match self {
+            CipherSuite::Aes128Siv => 0,
+            CipherSuite::ChaCha20Poly1305 => 1,
+            CipherSuite::Aes256Gcm => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, CoreError> {
+        match tag {
+            0 => Ok(CipherSuite::Aes128Siv),
+            1 => Ok(CipherSuite::ChaCha20Poly1305),
+            2 => Ok(CipherSuite::Aes256Gcm),
+            _ => Err(CoreError::SymmetricDecipherFailure),
+        }
+    }
+}
+
+/// A wrapper for sensitive material (symmetric keys, derived password/keyword/PIN secrets,
+/// decrypted `DataMap`s) that zeroes its contents as soon as it's dropped, so it doesn't linger
+/// in process memory where it could leak via a core dump or swap.
+pub struct Protected<T: Zeroize>(T);
+
+impl<T: Zeroize> Protected<T> {
+    /// Wrap `value` so its contents are zeroed on drop.
+    pub fn new(value: T) -> Self {
+        Protected(value)
+    }
+}
+
+impl<T: Zeroize> Deref for Protected<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> DerefMut for Protected<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Protected<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize + PartialEq> PartialEq for Protected<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T: Zeroize + Eq> Eq for Protected<T> {}
+
+impl<T: Zeroize> fmt::Debug for Protected<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Protected").field(&"<redacted>").finish()
+    }
+}
+
 /// Generates a symmetric encryption key
-pub fn generate_symm_enc_key() -> SymEncKey {
-    rand::random()
+pub fn generate_symm_enc_key() -> Protected<SymEncKey> {
+    Protected::new(rand::random())
 }
 
 /// Generates a nonce for symmetric encryption
@@ -91,12 +200,31 @@ pub fn generate_nonce() -> SymEncNonce {
     rand::random()
 }
 
-/// Symmetric encryption.
-/// If `nonce` is `None`, then it will be generated randomly.
+/// Symmetric encryption using the crate's default cipher suite (AES-128-SIV), with empty
+/// associated data. If `nonce` is `None`, then it will be generated randomly.
+///
+/// The `aad` taken by `symmetric_encrypt_with_aad` and `symmetric_encrypt_with_suite` binds the
+/// cipher text to a context of the caller's choosing (e.g. the data's `XorName`/type
+/// tag/version), so a ciphertext produced for one logical slot cannot be silently replayed into
+/// another. The same `aad` must be supplied to `symmetric_decrypt_with_aad` or decryption will
+/// fail authentication. Use `symmetric_encrypt` (no AAD) if the cipher text isn't bound to any
+/// particular context.
 pub fn symmetric_encrypt(
     plain_text: &[u8],
-    secret_key: &SymEncKey,
+    secret_key: &Protected<SymEncKey>,
     nonce: Option<&SymEncNonce>,
+) -> Result<Vec<u8>, CoreError> {
+    symmetric_encrypt_with_aad(plain_text, secret_key, nonce, &[])
+}
+
+/// Symmetric encryption with associated data, under the default AES-128-SIV suite. See
+/// `symmetric_encrypt` for details on `aad` and `symmetric_encrypt_with_suite` to pick a
+/// different cipher.
+pub fn symmetric_encrypt_with_aad(
+    plain_text: &[u8],
+    secret_key: &Protected<SymEncKey>,
+    nonce: Option<&SymEncNonce>,
+    aad: &[u8],
 ) -> Result<Vec<u8>, CoreError> {
     let nonce = match nonce {
         Some(nonce) => *nonce,
@@ -104,18 +232,135 @@ pub fn symmetric_encrypt(
     };
 
     let mut cipher = Aes128SivAead::new(secret_key);
-    let cipher_text = cipher.seal(&nonce, &[], plain_text);
+    let cipher_text = cipher.seal(&nonce, aad, plain_text);
 
-    Ok(serialize(&SymmetricEnc { nonce, cipher_text })?)
+    Ok(serialize(&SymmetricEnc {
+        suite: CipherSuite::Aes128Siv.tag(),
+        nonce: nonce.to_vec(),
+        cipher_text,
+    })?)
 }
 
-/// Symmetric decryption.
-pub fn symmetric_decrypt(cipher_text: &[u8], secret_key: &SymEncKey) -> Result<Vec<u8>, CoreError> {
-    let SymmetricEnc { nonce, cipher_text } = deserialize::<SymmetricEnc>(cipher_text)?;
-    let mut cipher = Aes128SivAead::new(secret_key);
-    cipher
-        .open(&nonce, &[], &cipher_text)
-        .map_err(|_| CoreError::SymmetricDecipherFailure)
+/// Symmetric encryption under an explicit `CipherSuite` rather than the crate's current default.
+/// This is what gives the crate crypto-agility: the suite travels with the cipher text, so data
+/// written with e.g. `CipherSuite::ChaCha20Poly1305` today decrypts correctly even if the
+/// default changes tomorrow. See `symmetric_encrypt` for details on `aad`.
+pub fn symmetric_encrypt_with_suite(
+    plain_text: &[u8],
+    secret_key: &Protected<SymEncKey>,
+    suite: CipherSuite,
+    aad: &[u8],
+) -> Result<Vec<u8>, CoreError> {
+    match suite {
+        CipherSuite::Aes128Siv => symmetric_encrypt_with_aad(plain_text, secret_key, None, aad),
+        CipherSuite::ChaCha20Poly1305 => {
+            let nonce: [u8; AEAD_NONCE_LEN] = rand::random();
+            let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&**secret_key));
+            let cipher_text = cipher
+                .encrypt(GenericArray::from_slice(&nonce), Payload { msg: plain_text, aad })
+                .map_err(|_| CoreError::SymmetricDecipherFailure)?;
+
+            Ok(serialize(&SymmetricEnc {
+                suite: suite.tag(),
+                nonce: nonce.to_vec(),
+                cipher_text,
+            })?)
+        }
+        CipherSuite::Aes256Gcm => {
+            let nonce: [u8; AEAD_NONCE_LEN] = rand::random();
+            let cipher = Aes256Gcm::new(GenericArray::from_slice(&**secret_key));
+            let cipher_text = cipher
+                .encrypt(GenericArray::from_slice(&nonce), Payload { msg: plain_text, aad })
+                .map_err(|_| CoreError::SymmetricDecipherFailure)?;
+
+            Ok(serialize(&SymmetricEnc {
+                suite: suite.tag(),
+                nonce: nonce.to_vec(),
+                cipher_text,
+            })?)
+        }
+    }
+}
+
+/// Symmetric decryption. Dispatches on the cipher suite tagged onto `cipher_text`, so blobs
+/// written under any previously-supported suite keep decrypting regardless of what
+/// `symmetric_encrypt`'s current default is.
+pub fn symmetric_decrypt(
+    cipher_text: &[u8],
+    secret_key: &Protected<SymEncKey>,
+) -> Result<Vec<u8>, CoreError> {
+    symmetric_decrypt_with_aad(cipher_text, secret_key, &[])
+}
+
+/// Symmetric decryption with associated data. `aad` must match the value passed at encryption
+/// time, or the cipher text will fail to authenticate. See `symmetric_encrypt` for details on
+/// `aad` and `symmetric_decrypt` for the suite dispatch.
+pub fn symmetric_decrypt_with_aad(
+    cipher_text: &[u8],
+    secret_key: &Protected<SymEncKey>,
+    aad: &[u8],
+) -> Result<Vec<u8>, CoreError> {
+    // Blobs written before cipher suites were tagged have no `suite` byte, so the fields don't
+    // line up with the current layout: reading them as `SymmetricEnc` either fails outright (the
+    // leading nonce byte read as a suite tag throws off every later length-prefixed field, almost
+    // always past the end of the buffer) or, rarely, happens to parse with an invalid suite tag.
+    // Either way, fall back to the legacy untagged layout as AES-128-SIV.
+    let (suite, nonce, cipher_text) = match deserialize::<SymmetricEnc>(cipher_text) {
+        Ok(enc) if CipherSuite::from_tag(enc.suite).is_ok() => (enc.suite, enc.nonce, enc.cipher_text),
+        _ => {
+            let legacy = deserialize::<LegacySymmetricEnc>(cipher_text)?;
+            (
+                CipherSuite::Aes128Siv.tag(),
+                legacy.nonce.to_vec(),
+                legacy.cipher_text,
+            )
+        }
+    };
+
+    match CipherSuite::from_tag(suite)? {
+        CipherSuite::Aes128Siv => {
+            if nonce.len() != SYM_ENC_NONCE_LEN {
+                return Err(CoreError::SymmetricDecipherFailure);
+            }
+            let mut siv_nonce = [0u8; SYM_ENC_NONCE_LEN];
+            siv_nonce.copy_from_slice(&nonce);
+
+            let mut cipher = Aes128SivAead::new(secret_key);
+            cipher
+                .open(&siv_nonce, aad, &cipher_text)
+                .map_err(|_| CoreError::SymmetricDecipherFailure)
+        }
+        CipherSuite::ChaCha20Poly1305 => {
+            if nonce.len() != AEAD_NONCE_LEN {
+                return Err(CoreError::SymmetricDecipherFailure);
+            }
+            let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&**secret_key));
+            cipher
+                .decrypt(
+                    GenericArray::from_slice(&nonce),
+                    Payload {
+                        msg: &cipher_text,
+                        aad,
+                    },
+                )
+                .map_err(|_| CoreError::SymmetricDecipherFailure)
+        }
+        CipherSuite::Aes256Gcm => {
+            if nonce.len() != AEAD_NONCE_LEN {
+                return Err(CoreError::SymmetricDecipherFailure);
+            }
+            let cipher = Aes256Gcm::new(GenericArray::from_slice(&**secret_key));
+            cipher
+                .decrypt(
+                    GenericArray::from_slice(&nonce),
+                    Payload {
+                        msg: &cipher_text,
+                        aad,
+                    },
+                )
+                .map_err(|_| CoreError::SymmetricDecipherFailure)
+        }
+    }
 }
 
 /// Generates a `String` from `length` random UTF-8 `char`s.  Note that the NULL character will be
@@ -182,15 +427,156 @@ where
         .collect()
 }
 
-/// Derive Password, Keyword and PIN (in order).
-pub fn derive_secrets(acc_locator: &[u8], acc_password: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+/// The password-derivation algorithm an account packet's `KdfParams` was produced with. Lets a
+/// login tell a legacy SHA-512-only account apart from one hardened with `scrypt`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum KdfAlgorithm {
+    /// Plain SHA-512 hashing, used before the `scrypt` KDF was introduced. Accounts still using
+    /// this are upgraded via `migrate_legacy_secrets` on their next successful login.
+    Sha512,
+    /// Memory-hard `scrypt` derivation (the current default).
+    Scrypt,
+}
+
+/// Cost parameters for the `scrypt` password/PIN derivation. Serialised alongside the account
+/// packet so a later login reproduces the exact derivation used at account-creation time, even
+/// if the crate's own defaults change afterwards.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct KdfParams {
+    /// Algorithm these parameters apply to.
+    pub algorithm: KdfAlgorithm,
+    /// log2 of the scrypt CPU/memory cost parameter `N`.
+    pub log_n: u8,
+    /// scrypt block size parameter `r`.
+    pub r: u32,
+    /// scrypt parallelisation parameter `p`.
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    /// `N = 2^17, r = 8, p = 1` for the password. The PIN is only ever used as a network lookup
+    /// key rather than as secret material on its own, so `derive_secrets_with_params` derives it
+    /// under a cheaper `N = 2^14` (see `KdfParams::pin_params`).
+    fn default() -> Self {
+        KdfParams {
+            algorithm: KdfAlgorithm::Scrypt,
+            log_n: 17,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    fn pin_params(&self) -> Self {
+        KdfParams {
+            log_n: 14,
+            ..*self
+        }
+    }
+
+    fn scrypt_params(&self) -> Result<ScryptParams, CoreError> {
+        ScryptParams::new(self.log_n, self.r, self.p).map_err(|_| CoreError::SymmetricDecipherFailure)
+    }
+}
+
+/// Derive Password, Keyword and PIN (in order) using `KdfParams::default()`.
+///
+/// `keyword` remains a plain SHA-512 hash of `acc_locator`: it's only ever used as a public
+/// network locator/salt, not as secret material, so it doesn't need to be brute-force resistant.
+/// `password` and `pin`, however, are derived from the user's (often low-entropy) passphrase, so
+/// they're hardened with memory-hard `scrypt` rather than a bare hash. Use
+/// `derive_secrets_with_params` to reproduce a derivation recorded under non-default
+/// `KdfParams`, and `migrate_legacy_secrets` to upgrade an account still using the original
+/// SHA-512-only derivation.
+pub fn derive_secrets(
+    acc_locator: &[u8],
+    acc_password: &[u8],
+) -> Result<(Protected<Vec<u8>>, Protected<Vec<u8>>, Protected<Vec<u8>>), CoreError> {
+    derive_secrets_with_params(acc_locator, acc_password, &KdfParams::default())
+}
+
+/// Derive Password, Keyword and PIN (in order) using the given `KdfParams`.
+///
+/// Dispatches on `params.algorithm`: `KdfAlgorithm::Sha512` reproduces the original
+/// `derive_secrets_legacy` derivation (ignoring the scrypt cost fields, which don't apply to it),
+/// while `KdfAlgorithm::Scrypt` runs the memory-hard path below. A `KdfParams` loaded back from a
+/// stored account packet must round-trip through whichever algorithm it was recorded under, or a
+/// legacy account would silently derive the wrong secrets.
+///
+/// Returns `Err` if `params` carries a scrypt cost outside the range `scrypt` accepts - e.g. a
+/// corrupted or tampered account packet - rather than panicking on attacker-influenced input.
+pub fn derive_secrets_with_params(
+    acc_locator: &[u8],
+    acc_password: &[u8],
+    params: &KdfParams,
+) -> Result<(Protected<Vec<u8>>, Protected<Vec<u8>>, Protected<Vec<u8>>), CoreError> {
+    if params.algorithm == KdfAlgorithm::Sha512 {
+        return Ok(derive_secrets_legacy(acc_locator, acc_password));
+    }
+
+    let Digest(locator_hash) = sha512::hash(acc_locator);
+    let keyword = locator_hash.to_vec();
+
+    let password = scrypt_derive(acc_password, &keyword, &params.scrypt_params()?);
+    let pin = scrypt_derive(
+        acc_password,
+        &keyword[..DIGESTBYTES / 2],
+        &params.pin_params().scrypt_params()?,
+    );
+
+    Ok((
+        Protected::new(password),
+        Protected::new(keyword),
+        Protected::new(pin),
+    ))
+}
+
+/// Derive Password, Keyword and PIN the way accounts created before the `scrypt` KDF was
+/// introduced did: plain SHA-512 hashing, with no memory-hardening of the passphrase. Kept only
+/// so existing accounts can still log in; see `migrate_legacy_secrets` to upgrade them.
+pub fn derive_secrets_legacy(
+    acc_locator: &[u8],
+    acc_password: &[u8],
+) -> (Protected<Vec<u8>>, Protected<Vec<u8>>, Protected<Vec<u8>>) {
     let Digest(locator_hash) = sha512::hash(acc_locator);
 
     let pin = sha512::hash(&locator_hash[DIGESTBYTES / 2..]).0.to_vec();
     let keyword = locator_hash.to_vec();
     let password = sha512::hash(acc_password).0.to_vec();
 
-    (password, keyword, pin)
+    (
+        Protected::new(password),
+        Protected::new(keyword),
+        Protected::new(pin),
+    )
+}
+
+/// Upgrade a legacy SHA-512-derived account to the `scrypt` KDF. Call this once a login using
+/// `derive_secrets_legacy` has succeeded: it re-derives the password/pin secrets under
+/// `KdfParams::default()`, returning the new secrets together with the params that should be
+/// persisted alongside the account packet from now on.
+pub fn migrate_legacy_secrets(
+    acc_locator: &[u8],
+    acc_password: &[u8],
+) -> Result<
+    (
+        (Protected<Vec<u8>>, Protected<Vec<u8>>, Protected<Vec<u8>>),
+        KdfParams,
+    ),
+    CoreError,
+> {
+    let params = KdfParams::default();
+    Ok((
+        derive_secrets_with_params(acc_locator, acc_password, &params)?,
+        params,
+    ))
+}
+
+fn scrypt_derive(passphrase: &[u8], salt: &[u8], params: &ScryptParams) -> Vec<u8> {
+    let mut output = vec![0u8; 64];
+    unwrap!(scrypt(passphrase, salt, params, &mut output));
+    output
 }
 
 /// Convert binary data to a diplay-able format
@@ -252,6 +638,59 @@ mod tests {
         assert_eq!(vec2.len(), SIZE);
     }
 
+    // Ciphertext bound to one AAD context must fail to authenticate under a different one.
+    #[test]
+    fn symmetric_encrypt_with_aad_binds_context() {
+        let key = generate_symm_enc_key();
+        let plain_text = unwrap!(generate_random_vector::<u8>(SIZE));
+
+        let cipher_text =
+            unwrap!(symmetric_encrypt_with_aad(&plain_text, &key, None, b"slot-a"));
+
+        assert_eq!(
+            unwrap!(symmetric_decrypt_with_aad(&cipher_text, &key, b"slot-a")),
+            plain_text
+        );
+        assert!(symmetric_decrypt_with_aad(&cipher_text, &key, b"slot-b").is_err());
+        assert!(symmetric_decrypt(&cipher_text, &key).is_err());
+    }
+
+    // A blob written before cipher suites were tagged (no `suite` byte, fixed-length nonce) must
+    // still decrypt as AES-128-SIV.
+    #[test]
+    fn legacy_untagged_blob_still_decrypts() {
+        let key = generate_symm_enc_key();
+        let plain_text = unwrap!(generate_random_vector::<u8>(SIZE));
+        let nonce = generate_nonce();
+
+        let mut cipher = Aes128SivAead::new(&key);
+        let cipher_text = cipher.seal(&nonce, &[], &plain_text);
+        let legacy = unwrap!(serialize(&LegacySymmetricEnc { nonce, cipher_text }));
+
+        assert_eq!(unwrap!(symmetric_decrypt(&legacy, &key)), plain_text);
+    }
+
+    // Every supported cipher suite should round-trip through `symmetric_decrypt`, which
+    // dispatches purely on the suite tag carried in the cipher text.
+    #[test]
+    fn symmetric_encrypt_with_suite_roundtrips() {
+        let key = generate_symm_enc_key();
+        let plain_text = unwrap!(generate_random_vector::<u8>(SIZE));
+
+        for suite in &[
+            CipherSuite::Aes128Siv,
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::Aes256Gcm,
+        ] {
+            let cipher_text =
+                unwrap!(symmetric_encrypt_with_suite(&plain_text, &key, *suite, b"ctx"));
+            assert_eq!(
+                unwrap!(symmetric_decrypt_with_aad(&cipher_text, &key, b"ctx")),
+                plain_text
+            );
+        }
+    }
+
     // Test derivation of distinct password, keyword, and pin secrets.
     #[test]
     fn secrets_derivation() {
@@ -259,7 +698,8 @@ mod tests {
         {
             let secret_0 = unwrap!(generate_random_string(SIZE));
             let secret_1 = unwrap!(generate_random_string(SIZE));
-            let (password, keyword, pin) = derive_secrets(secret_0.as_bytes(), secret_1.as_bytes());
+            let (password, keyword, pin) =
+                unwrap!(derive_secrets(secret_0.as_bytes(), secret_1.as_bytes()));
             assert_ne!(pin, keyword);
             assert_ne!(password, pin);
             assert_ne!(password, keyword);
@@ -269,10 +709,74 @@ mod tests {
         {
             let secret_0 = String::new();
             let secret_1 = String::new();
-            let (password, keyword, pin) = derive_secrets(secret_0.as_bytes(), secret_1.as_bytes());
+            let (password, keyword, pin) =
+                unwrap!(derive_secrets(secret_0.as_bytes(), secret_1.as_bytes()));
             assert_ne!(pin, keyword);
             assert_ne!(password, pin);
-            assert_eq!(password, keyword);
+            assert_ne!(password, keyword);
         }
+
+        // Derivation is deterministic for a given locator/password pair.
+        {
+            let locator = unwrap!(generate_random_string(SIZE));
+            let password = unwrap!(generate_random_string(SIZE));
+            let secrets_0 = unwrap!(derive_secrets(locator.as_bytes(), password.as_bytes()));
+            let secrets_1 = unwrap!(derive_secrets(locator.as_bytes(), password.as_bytes()));
+            assert_eq!(secrets_0, secrets_1);
+        }
+    }
+
+    // A legacy SHA-512-derived account should migrate to `scrypt` secrets that a subsequent
+    // call to `derive_secrets_with_params` with the returned params will then reproduce.
+    #[test]
+    fn legacy_secrets_migration() {
+        let locator = unwrap!(generate_random_string(SIZE));
+        let password = unwrap!(generate_random_string(SIZE));
+
+        let legacy_secrets = derive_secrets_legacy(locator.as_bytes(), password.as_bytes());
+        let (migrated_secrets, params) =
+            unwrap!(migrate_legacy_secrets(locator.as_bytes(), password.as_bytes()));
+
+        assert_ne!(migrated_secrets, legacy_secrets);
+        assert_eq!(params.algorithm, KdfAlgorithm::Scrypt);
+
+        let reproduced =
+            unwrap!(derive_secrets_with_params(locator.as_bytes(), password.as_bytes(), &params));
+        assert_eq!(reproduced, migrated_secrets);
+    }
+
+    // `KdfParams{algorithm: Sha512, ..}` loaded back from a stored account packet must reproduce
+    // the legacy derivation exactly, not fall through to scrypt.
+    #[test]
+    fn derive_secrets_with_params_dispatches_on_algorithm() {
+        let locator = unwrap!(generate_random_string(SIZE));
+        let password = unwrap!(generate_random_string(SIZE));
+
+        let legacy_secrets = derive_secrets_legacy(locator.as_bytes(), password.as_bytes());
+        let params = KdfParams {
+            algorithm: KdfAlgorithm::Sha512,
+            ..KdfParams::default()
+        };
+        let via_params =
+            unwrap!(derive_secrets_with_params(locator.as_bytes(), password.as_bytes(), &params));
+
+        assert_eq!(via_params, legacy_secrets);
+    }
+
+    // A `KdfParams` with a scrypt cost outside the valid range must be reported as an error, not
+    // panic - it may come straight from a corrupted or tampered account packet.
+    #[test]
+    fn derive_secrets_with_params_rejects_invalid_scrypt_params() {
+        let locator = unwrap!(generate_random_string(SIZE));
+        let password = unwrap!(generate_random_string(SIZE));
+
+        let params = KdfParams {
+            algorithm: KdfAlgorithm::Scrypt,
+            log_n: 255,
+            r: 8,
+            p: 1,
+        };
+
+        assert!(derive_secrets_with_params(locator.as_bytes(), password.as_bytes(), &params).is_err());
     }
 }
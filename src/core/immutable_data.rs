@@ -17,16 +17,79 @@
 
 use core::{Client, CoreError, CoreFuture, SelfEncryptionStorage, utility};
 use core::futures::FutureExt;
+use futures::future::{self, Loop};
 use futures::Future;
 use maidsafe_utilities::serialisation::{deserialise, serialise};
 use routing::{Data, DataIdentifier, ImmutableData, MAX_IMMUTABLE_DATA_SIZE_IN_BYTES, XorName};
+use rust_sodium::crypto::box_;
+use rust_sodium::crypto::hash::blake2b;
 use rust_sodium::crypto::secretbox;
 use self_encryption::{DataMap, SelfEncryptor};
+use zeroize::Zeroizing;
 
 #[derive(RustcEncodable, RustcDecodable)]
 enum DataTypeEncoding {
     Serialised(Vec<u8>),
     DataMap(DataMap),
+    SealedDataMap(Vec<u8>),
+}
+
+// `DataMap` carries the pre/post encryption hashes self-encryption derives each chunk's key
+// material from, so it's as sensitive as the symmetric keys in this module. We can't scrub it,
+// though: it's a foreign type from the `self_encryption` crate, so it can't implement the
+// `zeroize` crate's `Zeroize` trait here (neither type is local to this crate); and every call
+// site below hands it straight to `SelfEncryptor::new`, which takes ownership of it and keeps it
+// alive, unprotected, for as long as the encryptor lives - `SelfEncryptor` isn't ours to
+// instrument either. There's no window in which *we* hold the parsed `DataMap` long enough for a
+// scrub to do anything. The one thing we do protect is the serialised byte buffer it's parsed
+// from, via `Zeroizing` below, right up until `deserialise` consumes it.
+
+// NOTE on what this AAD scheme does and doesn't cover: the AAD is not bound to the data's
+// `XorName`, type tag or version. `ImmutableData`'s address is `hash(serialise(ImmutableData))`,
+// which includes this very cipher text, so the address isn't known before encryption produces
+// it. The AAD is instead a fingerprint of the plaintext `DataMap` plus a static call-site tag,
+// and it is stored in the clear beside the cipher text (see `EncryptedDataMap`). Decryption
+// authenticates the cipher text against that stored AAD only: it doesn't check which call site
+// (`create` or `Writer::finalize`) produced it, so either reader accepts either writer's output,
+// and a cipher text from one stored object grafted onto another object's slot, together with
+// its stored AAD, still authenticates there. What fails is pairing a cipher text with an AAD
+// value other than the one it was encrypted under.
+const CREATE_AAD_CONTEXT: &[u8] = b"core::immutable_data::create";
+const WRITER_AAD_CONTEXT: &[u8] = b"core::immutable_data::Writer::finalize";
+
+/// Wire format for a symmetrically-encrypted `DataMap`: the cipher text plus the associated data
+/// it was bound to, carried alongside in the clear (AAD isn't secret - it only has to be
+/// authenticated). Encryption/decryption always go through this pair together, so a cipher text
+/// from one call can't be paired with an AAD value belonging to another and still authenticate.
+#[derive(RustcEncodable, RustcDecodable)]
+struct EncryptedDataMap {
+    aad: Vec<u8>,
+    cipher_text: Vec<u8>,
+}
+
+// See the NOTE above `CREATE_AAD_CONTEXT`: this binds to a fingerprint of the plaintext `DataMap`
+// plus the call-site tag, not to the data's eventual address - that would be circular.
+fn data_map_aad(context: &[u8], serialised_data_map: &[u8]) -> Vec<u8> {
+    let mut hash_input = Vec::with_capacity(context.len() + serialised_data_map.len());
+    hash_input.extend_from_slice(context);
+    hash_input.extend_from_slice(serialised_data_map);
+
+    let blake2b::Digest(digest) = blake2b::hash(&hash_input);
+    digest.to_vec()
+}
+
+fn encrypt_data_map(serialised_data_map: &[u8],
+                    key: &secretbox::Key,
+                    context: &[u8])
+                    -> Result<Vec<u8>, CoreError> {
+    let aad = data_map_aad(context, serialised_data_map);
+    let cipher_text = utility::symmetric_encrypt_with_aad(serialised_data_map, key, &aad)?;
+    Ok(serialise(&EncryptedDataMap { aad, cipher_text })?)
+}
+
+fn decrypt_data_map(value: &[u8], key: &secretbox::Key) -> Result<Vec<u8>, CoreError> {
+    let EncryptedDataMap { aad, cipher_text } = deserialise(value)?;
+    Ok(utility::symmetric_decrypt_with_aad(&cipher_text, key, &aad)?)
 }
 
 /// Create and obtain immutable data out of the given raw bytes. The API will encrypt the right
@@ -49,7 +112,8 @@ pub fn create(client: &Client,
             let serialised_data_map = fry!(serialise(&data_map));
 
             let value = if let Some(key) = encryption_key {
-                let cipher_text = fry!(utility::symmetric_encrypt(&serialised_data_map, &key));
+                let cipher_text =
+                    fry!(encrypt_data_map(&serialised_data_map, &key, CREATE_AAD_CONTEXT));
                 fry!(serialise(&DataTypeEncoding::Serialised(cipher_text)))
             } else {
                 fry!(serialise(&DataTypeEncoding::Serialised(serialised_data_map)))
@@ -60,6 +124,34 @@ pub fn create(client: &Client,
         .into_box()
 }
 
+/// Create immutable data sealed to a recipient's public key, rather than a pre-shared secret
+/// key. This lets a caller share encrypted content with someone they only know the public key
+/// of, using a NaCl-style sealed box: an ephemeral keypair is generated for every call, the box
+/// nonce is derived from the ephemeral and recipient public keys, and the ephemeral public key
+/// is prepended to the resulting cipher text so the recipient can reconstruct the nonce on
+/// decryption.
+pub fn create_for(client: &Client,
+                  value: Vec<u8>,
+                  recipient_pk: box_::PublicKey)
+                  -> Box<CoreFuture<ImmutableData>> {
+    trace!("Creating conformant ImmutableData sealed to a recipient public key.");
+
+    let client = client.clone();
+    let storage = SelfEncryptionStorage::new(client.clone());
+    let self_encryptor = fry!(SelfEncryptor::new(storage, DataMap::None));
+
+    self_encryptor.write(&value, 0)
+        .and_then(move |_| self_encryptor.close())
+        .map_err(From::from)
+        .and_then(move |(data_map, _)| {
+            let sealed = fry!(seal_data_map(&data_map, &recipient_pk));
+            let value = fry!(serialise(&DataTypeEncoding::SealedDataMap(sealed)));
+
+            pack(client, value)
+        })
+        .into_box()
+}
+
 /// GET immutable data from the network.
 pub fn get(client: &Client, name: &XorName) -> Box<CoreFuture<ImmutableData>> {
     let data_id = DataIdentifier::Immutable(*name);
@@ -80,11 +172,13 @@ pub fn extract_value(client: &Client,
 
     unpack(client.clone(), data)
         .and_then(move |value| {
-            let data_map = if let Some(key) = decryption_key {
-                let plain_text =
-                    try!(utility::symmetric_decrypt(&value, &key));
+            // The serialised `DataMap` carries the per-chunk decryption keys for the whole
+            // object, so scrub it from memory as soon as we're done deserialising it.
+            let data_map: DataMap = if let Some(key) = decryption_key {
+                let plain_text = Zeroizing::new(try!(decrypt_data_map(&value, &key)));
                 try!(deserialise(&plain_text))
             } else {
+                let value = Zeroizing::new(value);
                 try!(deserialise(&value))
             };
 
@@ -98,6 +192,29 @@ pub fn extract_value(client: &Client,
         .into_box()
 }
 
+/// Get the raw bytes from ImmutableData created via `create_for()`, decrypting it with our own
+/// keypair rather than a pre-shared secret key.
+pub fn extract_value_for(client: &Client,
+                         data: ImmutableData,
+                         our_pk: box_::PublicKey,
+                         our_sk: box_::SecretKey)
+                         -> Box<CoreFuture<Vec<u8>>> {
+    let client = client.clone();
+
+    unpack(client.clone(), data)
+        .and_then(move |sealed| {
+            let data_map = try!(open_sealed_data_map(&sealed, &our_pk, &our_sk));
+
+            let storage = SelfEncryptionStorage::new(client);
+            Ok(try!(SelfEncryptor::new(storage, data_map)))
+        })
+        .and_then(|self_encryptor| {
+            let length = self_encryptor.len();
+            self_encryptor.read(0, length).map_err(From::from)
+        })
+        .into_box()
+}
+
 /// Get immutable data from the network and extract its value, decrypting it in
 /// the process (if keys provided).
 /// This is a convenience function combining `get` and `extract_value` into one
@@ -112,44 +229,197 @@ pub fn get_value(client: &Client,
         .into_box()
 }
 
-// TODO: consider rewriting these two function to not use recursion.
-
-fn pack(client: Client, value: Vec<u8>) -> Box<CoreFuture<ImmutableData>> {
-    let data = ImmutableData::new(value);
-    let serialised_data = fry!(serialise(&data));
+/// Streaming writer for immutable data content too large to hold in memory as a single `Vec<u8>`.
+/// Feed it content incrementally via `write_chunk`, then call `finalize` to encrypt (if
+/// requested) and upload the resulting `DataMap` as one or more `ImmutableData` chunks.
+pub struct Writer {
+    client: Client,
+    self_encryptor: SelfEncryptor<SelfEncryptionStorage>,
+}
 
-    if serialised_data.len() > MAX_IMMUTABLE_DATA_SIZE_IN_BYTES {
+impl Writer {
+    /// Start a new streaming write.
+    pub fn new(client: &Client) -> Result<Self, CoreError> {
+        let client = client.clone();
         let storage = SelfEncryptionStorage::new(client.clone());
-        let self_encryptor = fry!(SelfEncryptor::new(storage, DataMap::None));
-        self_encryptor.write(&serialised_data, 0)
-            .and_then(move |_| self_encryptor.close())
+        let self_encryptor = SelfEncryptor::new(storage, DataMap::None)?;
+
+        Ok(Writer { client, self_encryptor })
+    }
+
+    /// Append `bytes` to the object being written. Can be called repeatedly with chunks of any
+    /// size, so the full object never has to be held in memory at once.
+    pub fn write_chunk(&self, bytes: &[u8]) -> Box<CoreFuture<()>> {
+        let position = self.self_encryptor.len();
+        self.self_encryptor.write(bytes, position).map_err(From::from).into_box()
+    }
+
+    /// Finish the write, optionally encrypting the object's `DataMap` to `encryption_key`, and
+    /// pack the result into one or more `ImmutableData` chunks ready to `put` on the network.
+    pub fn finalize(self, encryption_key: Option<secretbox::Key>) -> Box<CoreFuture<ImmutableData>> {
+        let Writer { client, self_encryptor } = self;
+
+        self_encryptor.close()
             .map_err(From::from)
             .and_then(move |(data_map, _)| {
-                let value = fry!(serialise(&DataTypeEncoding::DataMap(data_map)));
+                let serialised_data_map = fry!(serialise(&data_map));
+
+                let value = if let Some(key) = encryption_key {
+                    let cipher_text =
+                        fry!(encrypt_data_map(&serialised_data_map, &key, WRITER_AAD_CONTEXT));
+                    fry!(serialise(&DataTypeEncoding::Serialised(cipher_text)))
+                } else {
+                    fry!(serialise(&DataTypeEncoding::Serialised(serialised_data_map)))
+                };
+
                 pack(client, value)
             })
             .into_box()
-    } else {
-        ok!(data)
     }
 }
 
-fn unpack(client: Client, data: ImmutableData) -> Box<CoreFuture<Vec<u8>>> {
-    match fry!(deserialise(data.value())) {
-        DataTypeEncoding::Serialised(value) => ok!(value),
-        DataTypeEncoding::DataMap(data_map) => {
+/// Streaming reader over stored immutable data content, for random-access reads without
+/// materialising the whole object in memory. Obtain one via `Reader::new`, passing the
+/// `ImmutableData` fetched with `get` and the same `decryption_key` (if any) the object was
+/// written with.
+pub struct Reader {
+    self_encryptor: SelfEncryptor<SelfEncryptionStorage>,
+}
+
+impl Reader {
+    /// Open a stored object for random-access reads.
+    pub fn new(client: &Client,
+               data: ImmutableData,
+               decryption_key: Option<secretbox::Key>)
+               -> Box<CoreFuture<Reader>> {
+        let client = client.clone();
+
+        unpack(client.clone(), data)
+            .and_then(move |value| {
+                // See `extract_value`: scrub the serialised `DataMap` as soon as we're done
+                // deserialising it, since it carries the object's per-chunk decryption keys.
+                let data_map: DataMap = if let Some(key) = decryption_key {
+                    let plain_text = Zeroizing::new(try!(decrypt_data_map(&value, &key)));
+                    try!(deserialise(&plain_text))
+                } else {
+                    let value = Zeroizing::new(value);
+                    try!(deserialise(&value))
+                };
+
+                let storage = SelfEncryptionStorage::new(client);
+                Ok(try!(SelfEncryptor::new(storage, data_map)))
+            })
+            .map(|self_encryptor| Reader { self_encryptor })
+            .into_box()
+    }
+
+    /// Total length of the underlying object, in bytes.
+    pub fn len(&self) -> u64 {
+        self.self_encryptor.len()
+    }
+
+    /// Read `len` bytes starting at `offset`, without materialising the rest of the object.
+    pub fn read_range(&self, offset: u64, len: u64) -> Box<CoreFuture<Vec<u8>>> {
+        self.self_encryptor.read(offset, len).map_err(From::from).into_box()
+    }
+}
+
+// `pack`/`unpack` used to recurse one "data-map-of-a-data-map" layer at a time, which meant the
+// call stack grew with the number of layers needed to bring an arbitrarily large object under
+// `MAX_IMMUTABLE_DATA_SIZE_IN_BYTES`. Both now drive the same wrapping/unwrapping work through a
+// `loop_fn` loop instead, so the call depth no longer grows with the number of layers it takes
+// to pack/unpack an object. Each pass still holds one whole layer's serialised bytes in memory.
+
+fn pack(client: Client, value: Vec<u8>) -> Box<CoreFuture<ImmutableData>> {
+    future::loop_fn((client, value), |(client, value)| {
+        let data = ImmutableData::new(value);
+        let serialised_data = fry!(serialise(&data));
+
+        if serialised_data.len() > MAX_IMMUTABLE_DATA_SIZE_IN_BYTES {
             let storage = SelfEncryptionStorage::new(client.clone());
-            let self_encryptor = fry!(SelfEncryptor::new(storage, data_map));
-            let length = self_encryptor.len();
-            self_encryptor.read(0, length)
+            let self_encryptor = fry!(SelfEncryptor::new(storage, DataMap::None));
+            self_encryptor.write(&serialised_data, 0)
+                .and_then(move |_| self_encryptor.close())
                 .map_err(From::from)
-                .and_then(move |serialised_data| {
-                    let data = fry!(deserialise(&serialised_data));
-                    unpack(client, data)
+                .and_then(move |(data_map, _)| {
+                    let value = fry!(serialise(&DataTypeEncoding::DataMap(data_map)));
+                    Ok(Loop::Continue((client, value)))
                 })
                 .into_box()
+        } else {
+            ok!(Loop::Break(data))
+        }
+    })
+        .into_box()
+}
+
+fn unpack(client: Client, data: ImmutableData) -> Box<CoreFuture<Vec<u8>>> {
+    future::loop_fn((client, data), |(client, data)| {
+        match fry!(deserialise(data.value())) {
+            DataTypeEncoding::Serialised(value) => ok!(Loop::Break(value)),
+            DataTypeEncoding::SealedDataMap(sealed) => ok!(Loop::Break(sealed)),
+            DataTypeEncoding::DataMap(data_map) => {
+                let storage = SelfEncryptionStorage::new(client.clone());
+                let self_encryptor = fry!(SelfEncryptor::new(storage, data_map));
+                let length = self_encryptor.len();
+                self_encryptor.read(0, length)
+                    .map_err(From::from)
+                    .and_then(move |serialised_data| {
+                        let data = fry!(deserialise(&serialised_data));
+                        Ok(Loop::Continue((client, data)))
+                    })
+                    .into_box()
+            }
         }
+    })
+        .into_box()
+}
+
+// Seal a serialised `DataMap` to `recipient_pk` using a NaCl-style sealed box: an ephemeral
+// keypair is generated for this call alone, the box is encrypted against `recipient_pk` using
+// the ephemeral secret key, and the ephemeral public key is prepended so the recipient can
+// recompute the nonce without any prior shared state.
+fn seal_data_map(data_map: &DataMap, recipient_pk: &box_::PublicKey) -> Result<Vec<u8>, CoreError> {
+    let serialised_data_map = serialise(data_map)?;
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    let nonce = derive_seal_nonce(&ephemeral_pk, recipient_pk);
+    let cipher_text = box_::seal(&serialised_data_map, &nonce, recipient_pk, &ephemeral_sk);
+
+    let mut sealed = Vec::with_capacity(box_::PUBLICKEYBYTES + cipher_text.len());
+    sealed.extend_from_slice(&ephemeral_pk.0);
+    sealed.extend_from_slice(&cipher_text);
+    Ok(sealed)
+}
+
+// Reverse of `seal_data_map`: split off the embedded ephemeral public key, rebuild the nonce
+// from it and our own public key, and open the box with our secret key.
+fn open_sealed_data_map(sealed: &[u8],
+                        our_pk: &box_::PublicKey,
+                        our_sk: &box_::SecretKey)
+                        -> Result<DataMap, CoreError> {
+    if sealed.len() < box_::PUBLICKEYBYTES {
+        return Err(CoreError::SymmetricDecipherFailure);
     }
+
+    let (ephemeral_pk_bytes, cipher_text) = sealed.split_at(box_::PUBLICKEYBYTES);
+    let ephemeral_pk = box_::PublicKey::from_slice(ephemeral_pk_bytes)
+        .ok_or(CoreError::SymmetricDecipherFailure)?;
+    let nonce = derive_seal_nonce(&ephemeral_pk, our_pk);
+
+    let plain_text = box_::open(cipher_text, &nonce, &ephemeral_pk, our_sk)
+        .map_err(|_| CoreError::SymmetricDecipherFailure)?;
+    Ok(deserialise(&plain_text)?)
+}
+
+fn derive_seal_nonce(ephemeral_pk: &box_::PublicKey, recipient_pk: &box_::PublicKey) -> box_::Nonce {
+    let mut hash_input = Vec::with_capacity(box_::PUBLICKEYBYTES * 2);
+    hash_input.extend_from_slice(&ephemeral_pk.0);
+    hash_input.extend_from_slice(&recipient_pk.0);
+
+    let blake2b::Digest(digest) = blake2b::hash(&hash_input);
+    let mut nonce = [0u8; box_::NONCEBYTES];
+    nonce.copy_from_slice(&digest[..box_::NONCEBYTES]);
+    box_::Nonce(nonce)
 }
 
 #[cfg(test)]
@@ -158,7 +428,7 @@ mod tests {
     use core::utility::test_utils;
     use futures::Future;
     use routing::Data;
-    use rust_sodium::crypto::secretbox;
+    use rust_sodium::crypto::{box_, secretbox};
     use super::*;
 
     #[test]
@@ -166,6 +436,87 @@ mod tests {
         create_and_retrieve(1024)
     }
 
+    // A `DataMap` encrypted for one call-site context (e.g. `create`) must not decrypt under a
+    // different context's AAD (e.g. `Writer::finalize`), even with the right key.
+    #[test]
+    fn encrypt_data_map_binds_to_context() {
+        let key = secretbox::gen_key();
+        let serialised_data_map = unwrap!(utility::generate_random_vector(128));
+
+        let encrypted = unwrap!(encrypt_data_map(&serialised_data_map, &key, CREATE_AAD_CONTEXT));
+        assert_eq!(unwrap!(decrypt_data_map(&encrypted, &key)), serialised_data_map);
+
+        let EncryptedDataMap { cipher_text, .. } = unwrap!(deserialise(&encrypted));
+        let grafted = unwrap!(serialise(&EncryptedDataMap {
+            aad: data_map_aad(WRITER_AAD_CONTEXT, &serialised_data_map),
+            cipher_text,
+        }));
+        assert!(decrypt_data_map(&grafted, &key).is_err());
+    }
+
+    #[test]
+    fn create_and_retrieve_sealed_to_public_key() {
+        let value = unwrap!(utility::generate_random_vector(1024));
+        let value_before = value.clone();
+        let (recipient_pk, recipient_sk) = box_::gen_keypair();
+
+        test_utils::register_and_run(move |client| {
+            let client2 = client.clone();
+            let client3 = client.clone();
+            let client4 = client.clone();
+
+            create_for(client, value_before.clone(), recipient_pk)
+                .and_then(move |data_before| {
+                    let data_name = *data_before.name();
+                    client2.put(Data::Immutable(data_before), None)
+                        .map(move |_| data_name)
+                })
+                .and_then(move |data_name| get(&client3, &data_name))
+                .and_then(move |data| extract_value_for(&client4, data, recipient_pk, recipient_sk))
+                .map(move |value_after| {
+                    assert_eq!(value_after, value_before);
+                })
+                .map_err(|error| panic!("Unexpected {:?}", error))
+        })
+    }
+
+    #[test]
+    fn streaming_write_and_ranged_read() {
+        let chunk_a = unwrap!(utility::generate_random_vector(1024));
+        let chunk_b = unwrap!(utility::generate_random_vector(2048));
+        let value_before: Vec<u8> = chunk_a.iter().chain(chunk_b.iter()).cloned().collect();
+        let total_len = value_before.len() as u64;
+
+        test_utils::register_and_run(move |client| {
+            let client2 = client.clone();
+            let client3 = client.clone();
+            let client4 = client.clone();
+
+            let writer = unwrap!(Writer::new(client));
+
+            writer.write_chunk(&chunk_a)
+                .and_then(move |_| {
+                    writer.write_chunk(&chunk_b)
+                        .and_then(move |_| writer.finalize(None))
+                })
+                .and_then(move |data_before| {
+                    let data_name = *data_before.name();
+                    client2.put(Data::Immutable(data_before), None)
+                        .map(move |_| data_name)
+                })
+                .and_then(move |data_name| get(&client3, &data_name))
+                .and_then(move |data| Reader::new(&client4, data, None))
+                .and_then(move |reader| {
+                    assert_eq!(reader.len(), total_len);
+                    reader.read_range(1024, 512)
+                })
+                .map(move |range| {
+                    assert_eq!(range, value_before[1024..1536].to_vec());
+                })
+                .map_err(|error| panic!("Unexpected {:?}", error))
+        })
+    }
+
     #[test]
     fn create_and_retrieve_1mb() {
         create_and_retrieve(1024 * 1024)